@@ -4,13 +4,82 @@ use super::{
     input, Connection, Display, DisplayBase, DisplayExt, PendingRequest, PendingRequestFlags,
     RequestCookie, RequestInfo, RequestWorkaround, EXT_KEY_SIZE,
 };
-use crate::{auto::xproto::QueryExtensionRequest, log_debug, log_trace, Fd, Request};
-use alloc::{string::ToString, vec, vec::Vec};
+use crate::{
+    auto::xproto::{GetInputFocusRequest, QueryExtensionRequest},
+    log_debug, log_trace, Fd, Request,
+};
+use alloc::{boxed::Box, collections::BTreeMap, string::ToString, vec, vec::Vec};
 use core::{iter, mem};
 use tinyvec::TinyVec;
 
 #[cfg(feature = "async")]
-use super::AsyncConnection;
+use super::{AsyncConnection, AsyncDisplay};
+
+/// The default number of bytes that may accumulate in an [`OutputBuffer`] before it is
+/// automatically flushed to the connection.
+pub(crate) const DEFAULT_OUTPUT_THRESHOLD: usize = 16 * 1024;
+
+/// A write-coalescing buffer that batches the raw bytes of several requests into a single
+/// `send_packet` call.
+///
+/// Draw-heavy workloads tend to issue long bursts of small, reply-less requests (e.g. a flood
+/// of `PolyFillRectangle`s). Handing each of those to `send_packet` individually is one syscall
+/// per request; instead we append the already-opcode-patched bytes here and only drain the buffer
+/// when it grows past [`threshold`](Self::threshold) bytes, when a request expects a reply, or on
+/// an explicit `flush()`.
+#[derive(Debug)]
+pub(crate) struct OutputBuffer {
+    data: Vec<u8>,
+    fds: Vec<Fd>,
+    threshold: usize,
+}
+
+impl Default for OutputBuffer {
+    #[inline]
+    fn default() -> Self {
+        OutputBuffer {
+            data: Vec::new(),
+            fds: Vec::new(),
+            threshold: DEFAULT_OUTPUT_THRESHOLD,
+        }
+    }
+}
+
+impl OutputBuffer {
+    /// Whether nothing is currently waiting to be flushed.
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Append a request's bytes (and any file descriptors) to the accumulation buffer.
+    #[inline]
+    pub(crate) fn push<I: IntoIterator<Item = Fd>>(&mut self, data: &[u8], fds: I) {
+        self.data.extend_from_slice(data);
+        self.fds.extend(fds);
+    }
+
+    /// Whether the accumulated bytes have crossed the flush threshold.
+    #[inline]
+    pub(crate) fn needs_flush(&self) -> bool {
+        self.data.len() >= self.threshold
+    }
+
+    /// Take the accumulated bytes and descriptors, leaving the buffer empty.
+    #[inline]
+    pub(crate) fn take(&mut self) -> (Vec<u8>, Vec<Fd>) {
+        (mem::take(&mut self.data), mem::take(&mut self.fds))
+    }
+}
+
+/// How close the span of unacknowledged requests may approach `2^16` before we force a
+/// round-trip to advance the server's view of the sequence counter.
+///
+/// X sequence numbers are only 16 bits on the wire, so if more than `2^16` requests are in flight
+/// without the server ever reporting a sequence back (a burst of reply-less requests), widening a
+/// truncated sequence becomes ambiguous. Syncing well before the wrap keeps [`widen_sequence`]
+/// unambiguous.
+pub(crate) const SEQUENCE_SYNC_THRESHOLD: u64 = (1 << 16) - 4096;
 
 #[inline]
 pub(crate) fn preprocess_request<D: DisplayBase + ?Sized>(
@@ -18,14 +87,147 @@ pub(crate) fn preprocess_request<D: DisplayBase + ?Sized>(
     mut pr: RequestInfo,
 ) -> RequestInfo {
     log_trace!("Entering preprocess_request()");
+    // keep the monotonic full count internally so replies carrying only a 16-bit sequence can be
+    // widened back to their full value; the wire only ever sees the low 16 bits
     let sequence = display.next_request_number();
-    // truncate to u16
+    display.set_request_number(sequence);
     let sequence = sequence as u16;
 
     pr.set_sequence(sequence);
     pr
 }
 
+/// Reconstruct the full 64-bit sequence number from a truncated 16-bit value `short` arriving on
+/// a reply, error, or event, given the last-known full sequence `last_full`.
+///
+/// The result is the most recent full sequence whose low 16 bits equal `short`, computed as
+/// `last_full - ((last_full - short) & 0xFFFF)`.
+#[inline]
+pub(crate) fn widen_sequence(last_full: u64, short: u16) -> u64 {
+    last_full.wrapping_sub(last_full.wrapping_sub(short as u64) & 0xFFFF)
+}
+
+/// Widen the truncated 16-bit sequence carried by an incoming reply, error, or event to the full
+/// 64-bit sequence that pending requests are keyed by, using the display's last-issued full
+/// sequence as the reference point. This is the lookup-side counterpart to the full-sequence
+/// registration performed in [`finish_request`].
+#[inline]
+pub(crate) fn widen_reply_sequence<D: DisplayBase + ?Sized>(display: &D, short: u16) -> u64 {
+    widen_sequence(display.request_number(), short)
+}
+
+/// If the span of requests issued since the last server acknowledgement is approaching the 16-bit
+/// wrap point, inject a throwaway `GetInputFocus` round-trip so the server advances its view of
+/// the sequence counter and widening stays unambiguous.
+#[inline]
+fn maybe_sync<D: Display + ?Sized>(display: &mut D) -> crate::Result<()> {
+    if display.request_number().wrapping_sub(display.last_sync_sequence()) < SEQUENCE_SYNC_THRESHOLD
+    {
+        return Ok(());
+    }
+
+    log_debug!("Outstanding sequence span approaching 2^16; injecting GetInputFocus sync");
+    // mark the span as synced *before* issuing the request, otherwise the injected GetInputFocus
+    // re-enters send_request with the guard still tripped and recurses unbounded
+    display.set_last_sync_sequence(display.request_number());
+    let tok = display.send_request(GetInputFocusRequest::default())?;
+    let _ = display.resolve_request(tok)?;
+    Ok(())
+}
+
+/// Async counterpart to [`maybe_sync`].
+#[cfg(feature = "async")]
+#[inline]
+async fn async_maybe_sync<D: AsyncDisplay + ?Sized>(display: &mut D) -> crate::Result<()> {
+    if display.request_number().wrapping_sub(display.last_sync_sequence()) < SEQUENCE_SYNC_THRESHOLD
+    {
+        return Ok(());
+    }
+
+    log_debug!("Outstanding sequence span approaching 2^16; injecting GetInputFocus sync");
+    // mark the span as synced *before* issuing the request, otherwise the injected GetInputFocus
+    // re-enters send_request with the guard still tripped and recurses unbounded
+    display.set_last_sync_sequence(display.request_number());
+    let tok = display.send_request_async(GetInputFocusRequest::default()).await?;
+    let _ = display.resolve_request_async(tok).await?;
+    Ok(())
+}
+
+/// A predicate that inspects an outgoing request for a known server-side quirk.
+///
+/// It receives the request's minor opcode and a read-only view of the request bytes and returns
+/// the [`RequestWorkaround`] to apply, if any. Predicates run after the bytes have been handed to
+/// the connection or appended to the output buffer, so they decide how the *reply* is tracked
+/// rather than rewriting the wire data.
+pub type WorkaroundPredicate = dyn Fn(u8, &[u8]) -> Option<RequestWorkaround> + Send + Sync;
+
+/// A registry of per-extension request workarounds, keyed by extension name.
+///
+/// `finish_request` consults the registry for the outgoing request's extension, so new server
+/// quirks can be handled by registering a predicate at runtime rather than editing core output
+/// code. Extensions (and users) may register their own quirks via [`register`](Self::register).
+#[derive(Default)]
+pub struct WorkaroundRegistry {
+    predicates: BTreeMap<&'static str, Vec<Box<WorkaroundPredicate>>>,
+}
+
+impl WorkaroundRegistry {
+    /// Create an empty registry with no workarounds installed.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry pre-populated with the workarounds breadx ships by default.
+    #[inline]
+    pub fn with_defaults() -> Self {
+        let mut this = Self::new();
+        this.register("GLX", glx_fbconfig_workaround);
+        this
+    }
+
+    /// Register a workaround predicate for the given extension name. Predicates are consulted in
+    /// registration order; the first one to return a workaround wins.
+    #[inline]
+    pub fn register<F>(&mut self, extension: &'static str, predicate: F)
+    where
+        F: Fn(u8, &[u8]) -> Option<RequestWorkaround> + Send + Sync + 'static,
+    {
+        self.predicates
+            .entry(extension)
+            .or_default()
+            .push(Box::new(predicate));
+    }
+
+    /// Resolve the workaround (if any) for a request belonging to `extension`, giving each
+    /// registered predicate a chance to inspect the outgoing `data`.
+    #[inline]
+    pub fn resolve(
+        &self,
+        extension: &str,
+        opcode: u8,
+        data: &[u8],
+    ) -> Option<RequestWorkaround> {
+        self.predicates
+            .get(extension)
+            .and_then(|preds| preds.iter().find_map(|p| p(opcode, data)))
+    }
+}
+
+/// The default GLX `FbConfig` workaround, previously hard-coded into `finish_request`.
+fn glx_fbconfig_workaround(opcode: u8, data: &[u8]) -> Option<RequestWorkaround> {
+    let field = data.get(32..36).map(|a| {
+        let mut arr: [u8; 4] = [0; 4];
+        arr.copy_from_slice(a);
+        u32::from_ne_bytes(arr)
+    });
+
+    match (opcode, field) {
+        (17, Some(0x10004)) | (21, _) => Some(RequestWorkaround::GlxFbconfigBug),
+        _ => None,
+    }
+}
+
 #[inline]
 pub(crate) fn finish_request<D: DisplayBase + ?Sized>(
     display: &mut D,
@@ -41,30 +243,30 @@ pub(crate) fn finish_request<D: DisplayBase + ?Sized>(
         ..Default::default()
     };
 
-    match (
-        pr.extension,
-        pr.opcode,
-        pr.data.get(32..36).map(|a| {
-            let mut arr: [u8; 4] = [0; 4];
-            arr.copy_from_slice(a);
-            u32::from_ne_bytes(arr)
-        }),
-    ) {
-        (Some("GLX"), 17, Some(0x10004)) | (Some("GLX"), 21, _) => {
-            log_debug!("Applying GLX FbConfig workaround to request");
-            flags.workaround = RequestWorkaround::GlxFbconfigBug;
+    // consult the display's workaround registry for any quirk affecting this extension's request
+    if let Some(extension) = pr.extension {
+        if let Some(workaround) =
+            display
+                .workaround_registry()
+                .resolve(extension, pr.opcode, &pr.data)
+        {
+            log_debug!("Applying {:?} workaround to request", workaround);
+            flags.workaround = workaround;
         }
-        _ => (),
     }
 
     let seq = pr.sequence.take().expect("Failed to set sequence number");
-    log_debug!("Got sequence number {}", seq);
+    // key the pending request by its full 64-bit sequence so reply matching stays unambiguous
+    // past a 16-bit wrap; derive it the same way the reader will when a reply arrives, so the
+    // registration and lookup keys always agree
+    let full = widen_sequence(display.request_number(), seq);
+    log_debug!("Got sequence number {} (full {})", seq, full);
 
     if !pr.zero_sized_reply || display.checked() {
         log_trace!(
             "Request is neither zero-sized nor is the display not checked, so we expect a reply"
         );
-        input::expect_reply(display, seq, flags);
+        input::expect_reply(display, full, flags);
     }
 
     Ok(seq)
@@ -95,6 +297,9 @@ pub(crate) fn send_request<D: Display + ?Sized, C: Connection + ?Sized>(
 ) -> crate::Result<u16> {
     log_trace!("Entering output::send_request()");
 
+    // make sure we haven't drifted too close to the 16-bit sequence wrap before issuing more
+    maybe_sync(display)?;
+
     let mut req = preprocess_request(display, request_info);
     // figure out the extension opcode
     let ext_opcode = match req.extension {
@@ -116,6 +321,24 @@ pub(crate) fn send_request<D: Display + ?Sized, C: Connection + ?Sized>(
     modify_for_opcode(&mut req.data, request_opcode, ext_opcode);
     log_trace!("We are sending the following request: {:?}", &req);
 
+    // if the display has an output buffer enabled, try to coalesce this request into it rather
+    // than issuing its own syscall
+    let expects_reply = !req.zero_sized_reply || display.checked();
+    if !expects_reply && req.fds.is_empty() {
+        if let Some(buffer) = display.output_buffer_mut() {
+            buffer.push(&req.data, iter::empty());
+            let needs_flush = buffer.needs_flush();
+            if needs_flush {
+                flush(display, connection)?;
+            }
+            return finish_request(display, req);
+        }
+    }
+
+    // this request either cannot be batched or carries file descriptors, so it forms a flush
+    // boundary: drain anything already buffered before emitting our own bytes
+    flush(display, connection)?;
+
     // send the packet
     log_debug!("Request is ready to send, beginning send_packet()");
     let mut fds = mem::take(&mut req.fds);
@@ -125,6 +348,23 @@ pub(crate) fn send_request<D: Display + ?Sized, C: Connection + ?Sized>(
     finish_request(display, req)
 }
 
+/// Flush any bytes accumulated in the display's [`OutputBuffer`] to the connection.
+#[inline]
+pub(crate) fn flush<D: Display + ?Sized, C: Connection + ?Sized>(
+    display: &mut D,
+    connection: &mut C,
+) -> crate::Result<()> {
+    let batch = match display.output_buffer_mut() {
+        Some(buffer) if !buffer.is_empty() => buffer.take(),
+        _ => return Ok(()),
+    };
+
+    let (data, mut fds) = batch;
+    log_debug!("Flushing {} buffered request bytes", data.len());
+    connection.send_packet(&data, &mut fds)?;
+    Ok(())
+}
+
 #[inline]
 pub(crate) fn get_ext_opcode<D: Display + ?Sized>(
     display: &mut D,
@@ -156,6 +396,114 @@ pub(crate) fn get_ext_opcode<D: Display + ?Sized>(
     Ok(repl.major_opcode)
 }
 
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn async_send_request<D: AsyncDisplay + ?Sized, C: AsyncConnection + ?Sized>(
+    display: &mut D,
+    connection: &mut C,
+    request_info: RequestInfo,
+) -> crate::Result<u16> {
+    log_trace!("Entering output::async_send_request()");
+
+    // make sure we haven't drifted too close to the 16-bit sequence wrap before issuing more
+    async_maybe_sync(display).await?;
+
+    let mut req = preprocess_request(display, request_info);
+    // figure out the extension opcode
+    let ext_opcode = match req.extension {
+        None => None,
+        Some(extension) => {
+            let key = str_to_key(extension);
+            match display.get_extension_opcode(&key) {
+                Some(opcode) => Some(opcode),
+                None => {
+                    let opcode = async_get_ext_opcode(display, extension).await?;
+                    display.set_extension_opcode(key, opcode);
+                    Some(opcode)
+                }
+            }
+        }
+    };
+
+    let request_opcode = req.opcode;
+    modify_for_opcode(&mut req.data, request_opcode, ext_opcode);
+    log_trace!("We are sending the following request: {:?}", &req);
+
+    // try to coalesce this request into the display's output buffer, exactly as the sync path does
+    let expects_reply = !req.zero_sized_reply || display.checked();
+    if !expects_reply && req.fds.is_empty() {
+        if let Some(buffer) = display.output_buffer_mut() {
+            buffer.push(&req.data, iter::empty());
+            let needs_flush = buffer.needs_flush();
+            if needs_flush {
+                async_flush(display, connection).await?;
+            }
+            return finish_request(display, req);
+        }
+    }
+
+    // flush boundary: drain the buffer before this request's own bytes
+    async_flush(display, connection).await?;
+
+    // send the packet
+    log_debug!("Request is ready to send, beginning send_packet()");
+    let mut fds = mem::take(&mut req.fds);
+    connection.send_packet(&req.data, &mut fds).await?;
+    log_debug!("Finished send_packet()");
+
+    finish_request(display, req)
+}
+
+/// Flush any bytes accumulated in the display's [`OutputBuffer`] to the async connection.
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn async_flush<D: AsyncDisplay + ?Sized, C: AsyncConnection + ?Sized>(
+    display: &mut D,
+    connection: &mut C,
+) -> crate::Result<()> {
+    let batch = match display.output_buffer_mut() {
+        Some(buffer) if !buffer.is_empty() => buffer.take(),
+        _ => return Ok(()),
+    };
+
+    let (data, mut fds) = batch;
+    log_debug!("Flushing {} buffered request bytes", data.len());
+    connection.send_packet(&data, &mut fds).await?;
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn async_get_ext_opcode<D: AsyncDisplay + ?Sized>(
+    display: &mut D,
+    extension: &'static str,
+) -> crate::Result<u8> {
+    log_trace!("Entering async_get_ext_opcode with extension: {}", extension);
+    log_debug!(
+        "Could not find extension opcode in display's database; sending request to server..."
+    );
+
+    let qer = QueryExtensionRequest {
+        name: extension.to_string(),
+        ..Default::default()
+    };
+
+    log_trace!("Sending QER..");
+    let tok = display.send_request_async(qer).await?;
+    log_trace!("Resolving QER...");
+    let repl = display.resolve_request_async(tok).await?;
+
+    if !repl.present {
+        return Err(crate::BreadError::ExtensionNotPresent(extension.into()));
+    }
+
+    log_debug!("Found opcode for extension: {}", &repl.major_opcode);
+    let key = str_to_key(extension);
+    display.set_extension_opcode(key, repl.major_opcode);
+    // TODO: first_event, first_error
+    Ok(repl.major_opcode)
+}
+
 #[inline]
 pub(crate) fn str_to_key(s: &str) -> [u8; EXT_KEY_SIZE] {
     let mut key = [0u8; EXT_KEY_SIZE];
@@ -163,3 +511,22 @@ pub(crate) fn str_to_key(s: &str) -> [u8; EXT_KEY_SIZE] {
     key.copy_from_slice(&b[..EXT_KEY_SIZE]);
     key
 }
+
+#[cfg(test)]
+mod tests {
+    use super::widen_sequence;
+
+    #[test]
+    fn widen_reconstructs_across_a_wrap() {
+        // a reply carrying 0xFFFF arriving just after the counter ticked past the 16-bit wrap
+        // must resolve to the pre-wrap full sequence 0xFFFF, not 0x1_FFFF in the current window
+        assert_eq!(widen_sequence(0x1_0002, 0xFFFF), 0xFFFF);
+    }
+
+    #[test]
+    fn widen_is_identity_for_the_current_sequence() {
+        for s in [0u64, 1, 0xFFFF, 0x1_0000, 0x1_2345, 0xDEAD_BEEF] {
+            assert_eq!(widen_sequence(s, s as u16), s);
+        }
+    }
+}
@@ -9,10 +9,26 @@ use core::{
     mem,
 };
 
+/// The rule used to decide which regions of a (possibly self-overlapping) shape are considered
+/// "inside" and therefore filled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FillRule {
+    /// A point is inside when a ray cast from it crosses an odd number of edges. This is the
+    /// classic alternate rule; it treats every edge crossing identically regardless of direction.
+    EvenOdd,
+    /// A point is inside when the signed number of edge crossings (counting direction) is
+    /// non-zero. This fills nested and self-overlapping polygons the way glyph outlines expect.
+    NonZero,
+}
+
 /// Tesselate a shape into a set of trapezoids. This function takes an iterator of points that represent a closed
-/// shape, and returns a semi-lazy iterator over the trapezoids.
+/// shape, and returns a semi-lazy iterator over the trapezoids. `rule` selects the fill rule used
+/// to resolve overlapping spans.
 #[inline]
-pub fn tesselate_shape<I: IntoIterator<Item = Pointfix>>(i: I) -> impl Iterator<Item = Trapezoid> {
+pub fn tesselate_shape<I: IntoIterator<Item = Pointfix>>(
+    i: I,
+    rule: FillRule,
+) -> impl Iterator<Item = Trapezoid> {
     // Note: it is more efficient to ignore horizontal edges
     edges_to_trapezoids(
         PointsToEdges {
@@ -21,11 +37,188 @@ pub fn tesselate_shape<I: IntoIterator<Item = Pointfix>>(i: I) -> impl Iterator<
             last: None,
         }
         .filter(|e| e.y1 != e.y2),
+        rule,
     )
 }
 
+/// A single element of a path description, mirroring the common "pen" model used by vector
+/// graphics APIs. A path is a sequence of these; curves are flattened into line segments by
+/// [`tesselate_path`] before tessellation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PathElement {
+    /// Begin a new subpath at the given point.
+    MoveTo(Pointfix),
+    /// Draw a straight line from the current point to the given point.
+    LineTo(Pointfix),
+    /// Draw a quadratic Bézier curve from the current point, using `ctrl` as the control point,
+    /// to `to`.
+    QuadTo { ctrl: Pointfix, to: Pointfix },
+    /// Draw a cubic Bézier curve from the current point, using `ctrl1` and `ctrl2` as control
+    /// points, to `to`.
+    CubicTo {
+        ctrl1: Pointfix,
+        ctrl2: Pointfix,
+        to: Pointfix,
+    },
+    /// Close the current subpath by drawing a line back to its starting point.
+    Close,
+}
+
+/// The maximum number of times a curve may be subdivided while flattening, guarding against
+/// pathological recursion on near-degenerate control polygons.
+const MAX_BEZIER_DEPTH: usize = 16;
+
+/// Tesselate a curved path into a set of trapezoids. Quadratic and cubic Bézier segments are
+/// adaptively flattened into straight [`Pointfix`] segments (to within `tolerance`, measured in
+/// fixed-point device units) and fed into the same edge/trapezoid pipeline as [`tesselate_shape`].
 #[inline]
-fn edges_to_trapezoids<I: IntoIterator<Item = Edge>>(i: I) -> Trapezoids {
+pub fn tesselate_path<I: IntoIterator<Item = PathElement>>(
+    elements: I,
+    tolerance: Fixed,
+    rule: FillRule,
+) -> impl Iterator<Item = Trapezoid> {
+    // tolerance is given in fixed-point device units; keep the flatness test in doubles to match
+    // the rest of the edge math
+    let tol = fixed_to_double(tolerance).abs().max(f64::EPSILON);
+
+    // Flatten each subpath into its own contour. We build edges per-contour (closing each one
+    // last->first) so no bridge edge is ever emitted between one subpath's end and the next's
+    // start, but all of those edges are then fed into a *single* sweepline. A single sweep is
+    // what lets parity/winding cancel inside a reverse-wound inner contour, leaving the hole in a
+    // multi-contour fill such as an "O" glyph empty.
+    let mut contours: Vec<Vec<Pointfix>> = Vec::new();
+    let mut current_contour: Vec<Pointfix> = Vec::new();
+    let mut current = Pointfix { x: 0, y: 0 };
+
+    for element in elements {
+        match element {
+            PathElement::MoveTo(p) => {
+                // begin a fresh subpath, finalizing any in-progress one
+                if !current_contour.is_empty() {
+                    contours.push(mem::take(&mut current_contour));
+                }
+                current_contour.push(p);
+                current = p;
+            }
+            PathElement::LineTo(p) => {
+                current_contour.push(p);
+                current = p;
+            }
+            PathElement::QuadTo { ctrl, to } => {
+                flatten_quad(&mut current_contour, current, ctrl, to, tol, 0);
+                current_contour.push(to);
+                current = to;
+            }
+            PathElement::CubicTo { ctrl1, ctrl2, to } => {
+                flatten_cubic(&mut current_contour, current, ctrl1, ctrl2, to, tol, 0);
+                current_contour.push(to);
+                current = to;
+            }
+            PathElement::Close => {
+                // the pipeline closes every contour last->first on its own, so closing just ends
+                // the subpath
+                if !current_contour.is_empty() {
+                    current = current_contour[0];
+                    contours.push(mem::take(&mut current_contour));
+                }
+            }
+        }
+    }
+    if !current_contour.is_empty() {
+        contours.push(current_contour);
+    }
+
+    // turn every contour into edges (closed last->first, horizontal edges dropped) and run them
+    // all through one sweepline so holes resolve correctly
+    let edges = contours.into_iter().flat_map(|contour| {
+        PointsToEdges {
+            inner: contour.into_iter().fuse(),
+            first: None,
+            last: None,
+        }
+        .filter(|e| e.y1 != e.y2)
+    });
+    edges_to_trapezoids(edges, rule)
+}
+
+/// The perpendicular distance from point `p` to the (infinite) line through `a` and `b`.
+#[inline]
+fn perpendicular_distance(p: Pointfix, a: Pointfix, b: Pointfix) -> f64 {
+    let (px, py) = (fixed_to_double(p.x), fixed_to_double(p.y));
+    let (ax, ay) = (fixed_to_double(a.x), fixed_to_double(a.y));
+    let (bx, by) = (fixed_to_double(b.x), fixed_to_double(b.y));
+
+    let dx = bx - ax;
+    let dy = by - ay;
+    let len = (dx * dx + dy * dy).sqrt();
+    if len <= f64::EPSILON {
+        // chord is degenerate; fall back to the distance to the endpoint
+        return ((px - ax).powi(2) + (py - ay).powi(2)).sqrt();
+    }
+
+    ((px - ax) * dy - (py - ay) * dx).abs() / len
+}
+
+/// The midpoint of two fixed-point points, used as the De Casteljau split point at t=0.5.
+#[inline]
+fn midpoint(a: Pointfix, b: Pointfix) -> Pointfix {
+    Pointfix {
+        x: (a.x + b.x) / 2,
+        y: (a.y + b.y) / 2,
+    }
+}
+
+/// Recursively flatten a quadratic Bézier into line segments, pushing only the interior split
+/// points (the start point is already in place and the caller pushes the final endpoint).
+fn flatten_quad(out: &mut Vec<Pointfix>, p0: Pointfix, p1: Pointfix, p2: Pointfix, tol: f64, depth: usize) {
+    if depth >= MAX_BEZIER_DEPTH || perpendicular_distance(p1, p0, p2) <= tol {
+        // flat enough: the chord approximates the curve
+        return;
+    }
+
+    // split at t=0.5 via De Casteljau
+    let p01 = midpoint(p0, p1);
+    let p12 = midpoint(p1, p2);
+    let mid = midpoint(p01, p12);
+
+    flatten_quad(out, p0, p01, mid, tol, depth + 1);
+    out.push(mid);
+    flatten_quad(out, mid, p12, p2, tol, depth + 1);
+}
+
+/// Recursively flatten a cubic Bézier into line segments, pushing only the interior split points
+/// (the start point is already in place and the caller pushes the final endpoint).
+#[allow(clippy::too_many_arguments)]
+fn flatten_cubic(
+    out: &mut Vec<Pointfix>,
+    p0: Pointfix,
+    p1: Pointfix,
+    p2: Pointfix,
+    p3: Pointfix,
+    tol: f64,
+    depth: usize,
+) {
+    if depth >= MAX_BEZIER_DEPTH
+        || (perpendicular_distance(p1, p0, p3).max(perpendicular_distance(p2, p0, p3)) <= tol)
+    {
+        return;
+    }
+
+    // split at t=0.5 via De Casteljau
+    let p01 = midpoint(p0, p1);
+    let p12 = midpoint(p1, p2);
+    let p23 = midpoint(p2, p3);
+    let p012 = midpoint(p01, p12);
+    let p123 = midpoint(p12, p23);
+    let mid = midpoint(p012, p123);
+
+    flatten_cubic(out, p0, p01, p012, mid, tol, depth + 1);
+    out.push(mid);
+    flatten_cubic(out, mid, p123, p23, p3, tol, depth + 1);
+}
+
+#[inline]
+fn edges_to_trapezoids<I: IntoIterator<Item = Edge>>(i: I, rule: FillRule) -> Trapezoids {
     let mut edges: Vec<Edge> = i.into_iter().collect();
     if edges.is_empty() {
         // yields nothing
@@ -34,6 +227,7 @@ fn edges_to_trapezoids<I: IntoIterator<Item = Edge>>(i: I) -> Trapezoids {
             active: vec![],
             inactive: vec![],
             queue: VecDeque::new(),
+            rule,
         };
     }
 
@@ -52,6 +246,7 @@ fn edges_to_trapezoids<I: IntoIterator<Item = Edge>>(i: I) -> Trapezoids {
         active: Vec::with_capacity(edges.len()),
         inactive: edges,
         queue: VecDeque::new(),
+        rule,
     }
 }
 
@@ -61,6 +256,7 @@ struct Trapezoids {
     inactive: Vec<Edge>,
     y: Fixed,
     queue: VecDeque<Trapezoid>,
+    rule: FillRule,
 }
 
 impl Trapezoids {
@@ -131,24 +327,39 @@ impl Trapezoids {
             .expect("Iteration should've ended by now");
 
         // generate trapezoids; push into queue so we return them
-        self.queue
-            .extend(self.active.chunks_exact(2).map(move |es| {
-                let e1 = es[0];
-                let e2 = es[1];
-
-                Trapezoid {
-                    top: y,
-                    bottom: next_y,
-                    left: Linefix {
-                        p1: Pointfix { x: e1.x1, y: e1.y1 },
-                        p2: Pointfix { x: e1.x2, y: e1.y2 },
-                    },
-                    right: Linefix {
-                        p1: Pointfix { x: e2.x1, y: e2.y1 },
-                        p2: Pointfix { x: e2.x2, y: e2.y2 },
-                    },
+        match self.rule {
+            FillRule::EvenOdd => {
+                // pair the sorted active edges left-to-right: every other gap is "inside"
+                self.queue
+                    .extend(self.active.chunks_exact(2).map(move |es| {
+                        trapezoid_between(&es[0], &es[1], y, next_y)
+                    }));
+            }
+            FillRule::NonZero => {
+                // walk the sorted active edges left-to-right accumulating the running winding
+                // total; a span opens at the edge where the total first becomes non-zero and
+                // closes at the edge where it returns to zero
+                // accumulate in an i32 so a pathological pile-up of edges at one x can't overflow
+                // the per-edge i8 winding direction
+                let mut winding = 0i32;
+                let mut left: Option<&Edge> = None;
+                for edge in self.active.iter() {
+                    let was_inside = winding != 0;
+                    winding += edge.winding as i32;
+                    let is_inside = winding != 0;
+
+                    match (was_inside, is_inside) {
+                        (false, true) => left = Some(edge),
+                        (true, false) => {
+                            if let Some(l) = left.take() {
+                                self.queue.push_back(trapezoid_between(l, edge, y, next_y));
+                            }
+                        }
+                        _ => {}
+                    }
                 }
-            }));
+            }
+        }
 
         self.y = next_y;
 
@@ -259,6 +470,24 @@ impl<I: Iterator<Item = Pointfix>> Iterator for PointsToEdges<I> {
 impl<I: Iterator<Item = Pointfix>> FusedIterator for PointsToEdges<I> {}
 impl<I: Iterator<Item = Pointfix> + ExactSizeIterator> ExactSizeIterator for PointsToEdges<I> {}
 
+/// Build the trapezoid bounded on the left by `e1` and on the right by `e2`, spanning the
+/// vertical range `[top, bottom]`.
+#[inline]
+fn trapezoid_between(e1: &Edge, e2: &Edge, top: Fixed, bottom: Fixed) -> Trapezoid {
+    Trapezoid {
+        top,
+        bottom,
+        left: Linefix {
+            p1: Pointfix { x: e1.x1, y: e1.y1 },
+            p2: Pointfix { x: e1.x2, y: e1.y2 },
+        },
+        right: Linefix {
+            p1: Pointfix { x: e2.x1, y: e2.y1 },
+            p2: Pointfix { x: e2.x2, y: e2.y2 },
+        },
+    }
+}
+
 /// An edge between two points.
 #[derive(Debug, Copy, Clone)]
 struct Edge {
@@ -267,6 +496,11 @@ struct Edge {
     x2: Fixed,
     y2: Fixed,
     current_x: Fixed,
+    /// The original traversal direction of the edge before the y-sort below normalized its
+    /// endpoints: `+1` when the shape went from `p1` to `p2` in increasing y (toward the bottom),
+    /// `-1` otherwise. Used by the
+    /// non-zero winding fill rule.
+    winding: i8,
 }
 
 impl Edge {
@@ -279,6 +513,7 @@ impl Edge {
                 x2: p2.x,
                 y2: p2.y,
                 current_x: 0,
+                winding: 1,
             }
         } else {
             Edge {
@@ -287,6 +522,7 @@ impl Edge {
                 x2: p1.x,
                 y2: p1.y,
                 current_x: 0,
+                winding: -1,
             }
         }
     }
@@ -320,3 +556,74 @@ impl Edge {
         double_to_fixed((b2 - b1) / (m2 - m1))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn pt(x: f64, y: f64) -> Pointfix {
+        Pointfix {
+            x: double_to_fixed(x),
+            y: double_to_fixed(y),
+        }
+    }
+
+    /// Whether the point `(px, py)` (in device units) lies inside the trapezoid.
+    fn contains(trap: &Trapezoid, px: f64, py: f64) -> bool {
+        let top = fixed_to_double(trap.top);
+        let bottom = fixed_to_double(trap.bottom);
+        if py < top || py > bottom {
+            return false;
+        }
+
+        // interpolate a linefix's x at the given y
+        let x_at = |line: &Linefix| -> f64 {
+            let (x1, y1) = (fixed_to_double(line.p1.x), fixed_to_double(line.p1.y));
+            let (x2, y2) = (fixed_to_double(line.p2.x), fixed_to_double(line.p2.y));
+            if (y2 - y1).abs() <= f64::EPSILON {
+                x1
+            } else {
+                x1 + (x2 - x1) * (py - y1) / (y2 - y1)
+            }
+        };
+
+        px >= x_at(&trap.left) && px <= x_at(&trap.right)
+    }
+
+    // A square outer contour with a smaller, reverse-wound inner contour (i.e. an "O"). Under the
+    // non-zero rule the interior of the inner contour must be left empty, while the ring between
+    // the two contours fills solid.
+    #[test]
+    fn nonzero_leaves_reverse_wound_hole_empty() {
+        let elements = vec![
+            // outer contour, counter-clockwise
+            PathElement::MoveTo(pt(0.0, 0.0)),
+            PathElement::LineTo(pt(10.0, 0.0)),
+            PathElement::LineTo(pt(10.0, 10.0)),
+            PathElement::LineTo(pt(0.0, 10.0)),
+            PathElement::Close,
+            // inner contour, wound the opposite way so it punches a hole
+            PathElement::MoveTo(pt(3.0, 3.0)),
+            PathElement::LineTo(pt(3.0, 7.0)),
+            PathElement::LineTo(pt(7.0, 7.0)),
+            PathElement::LineTo(pt(7.0, 3.0)),
+            PathElement::Close,
+        ];
+
+        let traps: Vec<Trapezoid> =
+            tesselate_path(elements, double_to_fixed(0.1), FillRule::NonZero).collect();
+
+        assert!(!traps.is_empty(), "ring should produce trapezoids");
+        // the centre of the hole must not be covered by any trapezoid
+        assert!(
+            traps.iter().all(|t| !contains(t, 5.0, 5.0)),
+            "non-zero winding must leave the reverse-wound interior empty"
+        );
+        // a point inside the ring must be covered
+        assert!(
+            traps.iter().any(|t| contains(t, 1.0, 5.0)),
+            "the ring between the contours must fill"
+        );
+    }
+}